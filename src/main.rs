@@ -3,12 +3,17 @@ use std::path::Path;
 use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use actix_files as fs;
 use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use lru::LruCache;
 use parquet::file::reader::{FileReader, SerializedFileReader};
 use parquet::record::Field;
-use rstar::{RTree, RTreeObject, AABB};
+use rstar::{PointDistance, RTree, RTreeObject, AABB};
 use image::{ImageBuffer, Rgba};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use walkdir::WalkDir;
 
 fn debug_enabled() -> bool {
@@ -23,10 +28,35 @@ macro_rules! debug_log {
     };
 }
 
+/// Crate-wide error type. Every fallible operation in the pipeline -- from
+/// reading Parquet files to encoding a tile to taking a shared-state lock --
+/// reports through this instead of panicking or being swallowed.
+#[derive(Debug, Error)]
+enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("image encode error: {0}")]
+    ImageEncode(#[from] image::ImageError),
+    #[error("shared-state lock was poisoned")]
+    LockPoisoned,
+    #[error("invalid date/time: {0}")]
+    BadDateTime(String),
+    #[error("no data available for the requested range")]
+    NoDataInRange,
+    #[error("{0}")]
+    BadColumn(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
 #[derive(Clone, Copy, Debug, Deserialize)]
 struct DataPoint {
     x: f64,
     y: f64,
+    mmsi: Option<i64>,
+    timestamp: DateTime<Utc>,
 }
 
 impl RTreeObject for DataPoint {
@@ -37,16 +67,122 @@ impl RTreeObject for DataPoint {
     }
 }
 
+impl PointDistance for DataPoint {
+    fn distance_2(&self, point: &[f64; 2]) -> f64 {
+        let dx = self.x - point[0];
+        let dy = self.y - point[1];
+        dx * dx + dy * dy
+    }
+}
+
+/// The time span covered by one bin, half-open on the end: `[start, end)`.
+#[derive(Clone, Copy, Debug, Serialize)]
+struct TimeBin {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+/// Points bucketed into fixed-width time bins, each with its own `RTree` so a
+/// tile request for a given time window only has to query the bins that
+/// intersect it instead of rebuilding a single tree on every range change.
+struct Bins {
+    bin_width: Duration,
+    bins: Vec<TimeBin>,
+    trees: Vec<RTree<DataPoint>>,
+}
+
+impl Bins {
+    fn empty(bin_width: Duration) -> Self {
+        Bins { bin_width, bins: Vec::new(), trees: Vec::new() }
+    }
+
+    /// Indices of bins whose span overlaps the half-open window `[start, end)`.
+    fn intersecting(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<usize> {
+        self.bins
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.start < end && b.end > start)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn all_indices(&self) -> Vec<usize> {
+        (0..self.trees.len()).collect()
+    }
+}
+
+fn bin_width_hours() -> f64 {
+    std::env::var("BIN_HOURS").ok().and_then(|v| v.parse().ok()).unwrap_or(1.0)
+}
+
+/// Bucket points into fixed-width bins spanning the dataset's min/max
+/// timestamp, building one `RTree` per bin.
+fn bucket_points(points: Vec<DataPoint>, bin_width: Duration) -> Bins {
+    let (Some(min_time), Some(max_time)) = (
+        points.iter().map(|p| p.timestamp).min(),
+        points.iter().map(|p| p.timestamp).max(),
+    ) else {
+        return Bins::empty(bin_width);
+    };
+
+    let bin_width_ms = bin_width.num_milliseconds().max(1);
+    let bin_count = ((max_time - min_time).num_milliseconds() / bin_width_ms) as usize + 1;
+
+    let bins: Vec<TimeBin> = (0..bin_count)
+        .map(|i| {
+            let start = min_time + Duration::milliseconds(bin_width_ms * i as i64);
+            TimeBin { start, end: start + bin_width }
+        })
+        .collect();
+
+    let mut bucketed: Vec<Vec<DataPoint>> = vec![Vec::new(); bin_count];
+    for p in points {
+        let offset_ms = (p.timestamp - min_time).num_milliseconds();
+        let idx = ((offset_ms / bin_width_ms) as usize).min(bin_count - 1);
+        bucketed[idx].push(p);
+    }
+
+    let trees: Vec<RTree<DataPoint>> = bucketed.into_iter().map(RTree::bulk_load).collect();
+    debug_log!("bucketed {} points into {} bins of {:?}", trees.iter().map(RTree::size).sum::<usize>(), bin_count, bin_width);
+    Bins { bin_width, bins, trees }
+}
+
+/// Key for the encoded-tile cache. Includes every request parameter that
+/// affects the rendered pixels, plus `dataset_version` so a cache built
+/// against a previous `/range` load is never served after the data changes.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct TileCacheKey {
+    zoom: u32,
+    x: u32,
+    y: u32,
+    dataset_version: u64,
+    indices: Vec<usize>,
+    cmap: ColorMap,
+    norm: Normalization,
+    fixed_max_bits: u32,
+}
+
+const TILE_CACHE_CAPACITY: usize = 512;
+
 struct AppState {
-    tree: Arc<Mutex<RTree<DataPoint>>>,
+    bins: Arc<RwLock<Bins>>,
+    points: Arc<RwLock<Vec<DataPoint>>>,
+    tile_cache: Arc<Mutex<LruCache<TileCacheKey, Vec<u8>>>>,
+    dataset_version: Arc<AtomicU64>,
 }
 
-fn tile2mercator(xtile: u32, ytile: u32, zoom: u32) -> (f64, f64) {
-    debug_log!("tile2mercator xtile={} ytile={} zoom={}", xtile, ytile, zoom);
+/// Lon/lat (degrees) of a tile's top-left corner in the slippy-map XYZ scheme.
+fn tile2lnglat(xtile: u32, ytile: u32, zoom: u32) -> (f64, f64) {
     let n = 2f64.powi(zoom as i32);
     let lon_deg = xtile as f64 / n * 360.0 - 180.0;
     let lat_rad = ((std::f64::consts::PI * (1.0 - 2.0 * ytile as f64 / n)).sinh()).atan();
     let lat_deg = lat_rad.to_degrees();
+    (lon_deg, lat_deg)
+}
+
+fn tile2mercator(xtile: u32, ytile: u32, zoom: u32) -> (f64, f64) {
+    debug_log!("tile2mercator xtile={} ytile={} zoom={}", xtile, ytile, zoom);
+    let (lon_deg, lat_deg) = tile2lnglat(xtile, ytile, zoom);
     let res = lnglat_to_meters(lon_deg, lat_deg);
     debug_log!("tile2mercator result lon={} lat={} -> ({}, {})", lon_deg, lat_deg, res.0, res.1);
     res
@@ -71,14 +207,163 @@ fn lnglat_to_meters(lon: f64, lat: f64) -> (f64, f64) {
     (x, y)
 }
 
-fn generate_tile(zoom: u32, x: u32, y: u32, tree: &RTree<DataPoint>) -> Vec<u8> {
-    debug_log!("generate_tile z={} x={} y={}", zoom, x, y);
+/// Great-circle distance between two lon/lat points, in meters.
+fn haversine_distance_m(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let phi1 = lat1.to_radians();
+    let phi2 = lat2.to_radians();
+    let dphi = (lat2 - lat1).to_radians();
+    let dlambda = (lon2 - lon1).to_radians();
+    let a = (dphi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (dlambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// Selectable density ramp for a tile. `RedBlue` is the original hardcoded
+/// look and stays the default so existing tile URLs render unchanged.
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum ColorMap {
+    #[default]
+    RedBlue,
+    Grayscale,
+    Viridis,
+    Inferno,
+}
+
+impl ColorMap {
+    fn apply(self, v: f32) -> Rgba<u8> {
+        if !v.is_finite() || v <= 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        match self {
+            ColorMap::RedBlue => red_blue_ramp(v),
+            ColorMap::Grayscale => grayscale_ramp(v),
+            ColorMap::Viridis => lut_ramp(viridis_lut(), v),
+            ColorMap::Inferno => lut_ramp(inferno_lut(), v),
+        }
+    }
+}
+
+fn red_blue_ramp(v: f32) -> Rgba<u8> {
+    // Intensify red as density increases
+    let intensity = v.powf(0.5).clamp(0.0, 1.0);
+    let r = (255.0 * intensity) as u8;
+    let b = 255 - r;
+    Rgba([r, 0, b, 255])
+}
+
+fn grayscale_ramp(v: f32) -> Rgba<u8> {
+    let level = (255.0 * v.clamp(0.0, 1.0)) as u8;
+    Rgba([level, level, level, 255])
+}
+
+fn lut_ramp(lut: &[Rgba<u8>; 256], v: f32) -> Rgba<u8> {
+    let idx = ((v.clamp(0.0, 1.0) * 255.0).round() as usize).min(255);
+    lut[idx]
+}
+
+const VIRIDIS_STOPS: [(f32, [u8; 3]); 9] = [
+    (0.00, [68, 1, 84]),
+    (0.13, [72, 40, 120]),
+    (0.25, [62, 74, 137]),
+    (0.38, [49, 104, 142]),
+    (0.50, [38, 130, 142]),
+    (0.63, [31, 158, 137]),
+    (0.75, [53, 183, 121]),
+    (0.88, [109, 205, 89]),
+    (1.00, [253, 231, 37]),
+];
+
+const INFERNO_STOPS: [(f32, [u8; 3]); 9] = [
+    (0.00, [0, 0, 4]),
+    (0.13, [31, 12, 72]),
+    (0.25, [85, 15, 109]),
+    (0.38, [136, 34, 106]),
+    (0.50, [186, 54, 85]),
+    (0.63, [227, 89, 51]),
+    (0.75, [249, 140, 10]),
+    (0.88, [249, 201, 50]),
+    (1.00, [252, 255, 164]),
+];
+
+/// Build a 256-entry lookup table by linearly interpolating between a
+/// perceptually-ordered set of color stops.
+fn build_lut(stops: &[(f32, [u8; 3])]) -> [Rgba<u8>; 256] {
+    let mut lut = [Rgba([0, 0, 0, 255]); 256];
+    for (i, entry) in lut.iter_mut().enumerate() {
+        let t = i as f32 / 255.0;
+        let mut lo = 0;
+        while lo + 1 < stops.len() && stops[lo + 1].0 < t {
+            lo += 1;
+        }
+        let hi = (lo + 1).min(stops.len() - 1);
+        let (t0, c0) = stops[lo];
+        let (t1, c1) = stops[hi];
+        let f = ((t - t0) / (t1 - t0).max(f32::EPSILON)).clamp(0.0, 1.0);
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * f).round() as u8;
+        *entry = Rgba([mix(c0[0], c1[0]), mix(c0[1], c1[1]), mix(c0[2], c1[2]), 255]);
+    }
+    lut
+}
+
+fn viridis_lut() -> &'static [Rgba<u8>; 256] {
+    static LUT: OnceLock<[Rgba<u8>; 256]> = OnceLock::new();
+    LUT.get_or_init(|| build_lut(&VIRIDIS_STOPS))
+}
+
+fn inferno_lut() -> &'static [Rgba<u8>; 256] {
+    static LUT: OnceLock<[Rgba<u8>; 256]> = OnceLock::new();
+    LUT.get_or_init(|| build_lut(&INFERNO_STOPS))
+}
+
+/// Selectable normalization for turning a raw per-pixel count into `[0, 1]`.
+/// `Log` matches the original hardcoded behavior and stays the default.
+/// `Fixed` ignores the tile's own max count so tiles at different zoom
+/// levels (and therefore different densities) stay visually comparable.
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Normalization {
+    #[default]
+    Log,
+    Linear,
+    Fixed,
+}
+
+const DEFAULT_FIXED_MAX: f32 = 50.0;
+
+fn normalize(cnt: u32, max_count: u32, norm: Normalization, fixed_max: f32) -> f32 {
+    match norm {
+        Normalization::Log => (cnt as f32).ln_1p() / (max_count as f32).ln_1p(),
+        Normalization::Linear => cnt as f32 / max_count as f32,
+        Normalization::Fixed => cnt as f32 / fixed_max,
+    }
+}
+
+/// Render a density tile from the union of the given bin indices.
+/// `DataPoint` stores raw WGS84 degrees, so the spatial query against each
+/// bin's tree is built in degrees too; only once a point has passed that
+/// filter is it projected to Web Mercator meters for placement in the
+/// tile's pixel grid (which is itself in meters, via `tile2mercator`).
+#[allow(clippy::too_many_arguments)]
+fn generate_tile(
+    zoom: u32,
+    x: u32,
+    y: u32,
+    bins: &Bins,
+    indices: &[usize],
+    cmap: ColorMap,
+    norm: Normalization,
+    fixed_max: f32,
+) -> Result<Vec<u8>> {
+    debug_log!("generate_tile z={} x={} y={} bins={:?} cmap={:?} norm={:?}", zoom, x, y, indices, cmap, norm);
     let (xleft, ytop) = tile2mercator(x, y, zoom);
     let (xright, ybottom) = tile2mercator(x + 1, y + 1, zoom);
 
-    let bbox = AABB::from_corners([xleft, ybottom], [xright, ytop]);
-    debug_log!("bbox: [{}, {}]-[{}, {}]", xleft, ybottom, xright, ytop);
-    let points = tree.locate_in_envelope(&bbox);
+    let (lon_left, lat_top) = tile2lnglat(x, y, zoom);
+    let (lon_right, lat_bottom) = tile2lnglat(x + 1, y + 1, zoom);
+    let query_bbox = AABB::from_corners([lon_left, lat_bottom], [lon_right, lat_top]);
+    debug_log!("query_bbox: [{}, {}]-[{}, {}]", lon_left, lat_bottom, lon_right, lat_top);
+    let points = indices.iter().flat_map(|&i| bins.trees[i].locate_in_envelope(&query_bbox));
 
     let width = 256u32;
     let height = 256u32;
@@ -86,8 +371,9 @@ fn generate_tile(zoom: u32, x: u32, y: u32, tree: &RTree<DataPoint>) -> Vec<u8>
     let mut point_count = 0u32;
 
     for p in points {
-        let px = ((p.x - xleft) / (xright - xleft) * width as f64) as i32;
-        let py = ((ytop - p.y) / (ytop - ybottom) * height as f64) as i32;
+        let (mx, my) = lnglat_to_meters(p.x, p.y);
+        let px = ((mx - xleft) / (xright - xleft) * width as f64) as i32;
+        let py = ((ytop - my) / (ytop - ybottom) * height as f64) as i32;
         if px >= 0 && px < width as i32 && py >= 0 && py < height as i32 {
             let idx = (py as u32 * width + px as u32) as usize;
             counts[idx] += 1;
@@ -103,43 +389,238 @@ fn generate_tile(zoom: u32, x: u32, y: u32, tree: &RTree<DataPoint>) -> Vec<u8>
     let mut img = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height);
     if max_count > 0 {
         for (i, cnt) in counts.into_iter().enumerate() {
-            // Use a logarithmic scale so areas of high density ramp up toward red
-            let val = (cnt as f32).ln_1p() / (max_count as f32).ln_1p();
-            let color = color_map(val);
+            let val = normalize(cnt, max_count, norm, fixed_max);
+            let color = cmap.apply(val);
             let x = (i as u32) % width;
             let y = (i as u32) / width;
             img.put_pixel(x, y, color);
         }
     }
 
+    encode_png(img)
+}
+
+fn encode_png(img: ImageBuffer<Rgba<u8>, Vec<u8>>) -> Result<Vec<u8>> {
     use std::io::Cursor;
     let mut bytes: Vec<u8> = Vec::new();
     {
         let mut cursor = Cursor::new(&mut bytes);
-        image::DynamicImage::ImageRgba8(img)
-            .write_to(&mut cursor, image::ImageFormat::Png)
-            .unwrap();
+        image::DynamicImage::ImageRgba8(img).write_to(&mut cursor, image::ImageFormat::Png)?;
     }
-    bytes
+    Ok(bytes)
 }
 
-fn color_map(v: f32) -> Rgba<u8> {
-    if !v.is_finite() || v <= 0.0 {
-        return Rgba([0, 0, 0, 0]);
+// A vessel can't teleport between fixes: if the implied speed between two
+// consecutive points exceeds this, or too much time has passed, the track
+// is broken into a new segment instead of drawing a bogus line across it.
+const TRACK_GAP_MINUTES: i64 = 30;
+const MAX_VESSEL_SPEED_MPS: f64 = 25.0; // ~49 knots, a generous ceiling for a large vessel
+
+/// Group points by MMSI, order each vessel's fixes by time, and split into
+/// contiguous segments wherever the time gap or implied speed is
+/// unreasonable. Points with no MMSI can't be attributed to a vessel and are
+/// dropped. Segments of a single point (nothing to connect) are discarded.
+fn build_tracks(points: &[DataPoint]) -> Vec<Vec<DataPoint>> {
+    let mut by_mmsi: HashMap<i64, Vec<DataPoint>> = HashMap::new();
+    for p in points {
+        if let Some(mmsi) = p.mmsi {
+            by_mmsi.entry(mmsi).or_default().push(*p);
+        }
     }
-    // Intensify red as density increases
-    let intensity = v.powf(0.5).clamp(0.0, 1.0);
-    let r = (255.0 * intensity) as u8;
-    let b = 255 - r;
-    debug_log!("color_map v={} -> r={} b={}", v, r, b);
-    Rgba([r, 0, b, 255])
+
+    let max_gap = Duration::minutes(TRACK_GAP_MINUTES);
+    let mut segments = Vec::new();
+    for (mmsi, mut fixes) in by_mmsi {
+        fixes.sort_by_key(|p| p.timestamp);
+        let mut current: Vec<DataPoint> = Vec::new();
+        for p in fixes {
+            if let Some(prev) = current.last() {
+                let elapsed = p.timestamp - prev.timestamp;
+                let dt_secs = elapsed.num_milliseconds() as f64 / 1000.0;
+                let dist_m = haversine_distance_m(prev.x, prev.y, p.x, p.y);
+                let speed_mps = if dt_secs > 0.0 { dist_m / dt_secs } else { f64::INFINITY };
+                if elapsed > max_gap || speed_mps > MAX_VESSEL_SPEED_MPS {
+                    debug_log!(
+                        "breaking track for mmsi {}: gap={:?} speed={:.1} m/s",
+                        mmsi, elapsed, speed_mps
+                    );
+                    if current.len() > 1 {
+                        segments.push(std::mem::take(&mut current));
+                    } else {
+                        current.clear();
+                    }
+                }
+            }
+            current.push(p);
+        }
+        if current.len() > 1 {
+            segments.push(current);
+        }
+    }
+    debug_log!("built {} track segments", segments.len());
+    segments
+}
+
+/// Clip a line segment to a `[0, width] x [0, height]` box using
+/// Liang-Barsky, so a track that runs far outside the tile doesn't cost a
+/// giant Bresenham walk and so only the visible portion is drawn.
+fn clip_line_to_box(x0: f64, y0: f64, x1: f64, y1: f64, width: f64, height: f64) -> Option<(f64, f64, f64, f64)> {
+    let dx = x1 - x0;
+    let dy = y1 - y0;
+    let mut t0 = 0.0f64;
+    let mut t1 = 1.0f64;
+    for (p, q) in [(-dx, x0), (dx, width - x0), (-dy, y0), (dy, height - y0)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let r = q / p;
+        if p < 0.0 {
+            if r > t1 {
+                return None;
+            }
+            if r > t0 {
+                t0 = r;
+            }
+        } else {
+            if r < t0 {
+                return None;
+            }
+            if r < t1 {
+                t1 = r;
+            }
+        }
+    }
+    Some((x0 + t0 * dx, y0 + t0 * dy, x0 + t1 * dx, y0 + t1 * dy))
+}
+
+/// Rasterize a line with Bresenham's algorithm, skipping pixels outside the
+/// canvas rather than requiring the caller to clip exactly.
+#[allow(clippy::too_many_arguments)]
+fn draw_line(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, x0: i32, y0: i32, x1: i32, y1: i32, color: Rgba<u8>, width: i32, height: i32) {
+    let (mut x0, mut y0) = (x0, y0);
+    let dx = (x1 - x0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let dy = -(y1 - y0).abs();
+    let sy = if y0 < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
+    loop {
+        if x0 >= 0 && x0 < width && y0 >= 0 && y0 < height {
+            img.put_pixel(x0 as u32, y0 as u32, color);
+        }
+        if x0 == x1 && y0 == y1 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x0 += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y0 += sy;
+        }
+    }
+}
+
+const TRACK_COLOR: Rgba<u8> = Rgba([0, 200, 255, 255]);
+
+/// Render vessel tracks for a tile: reconstruct segments from the full point
+/// set, project each segment's fixes to pixel space the same way
+/// `generate_tile` does (degrees -> Mercator meters -> pixels), and draw
+/// connecting lines instead of scattered dots.
+fn generate_track_tile(zoom: u32, x: u32, y: u32, points: &[DataPoint]) -> Result<Vec<u8>> {
+    debug_log!("generate_track_tile z={} x={} y={}", zoom, x, y);
+    let (xleft, ytop) = tile2mercator(x, y, zoom);
+    let (xright, ybottom) = tile2mercator(x + 1, y + 1, zoom);
+
+    let width = 256u32;
+    let height = 256u32;
+    let mut img = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height);
+
+    let to_pixel = |p: &DataPoint| -> (f64, f64) {
+        let (mx, my) = lnglat_to_meters(p.x, p.y);
+        let px = (mx - xleft) / (xright - xleft) * width as f64;
+        let py = (ytop - my) / (ytop - ybottom) * height as f64;
+        (px, py)
+    };
+
+    for segment in build_tracks(points) {
+        for pair in segment.windows(2) {
+            let (px0, py0) = to_pixel(&pair[0]);
+            let (px1, py1) = to_pixel(&pair[1]);
+            if let Some((cx0, cy0, cx1, cy1)) =
+                clip_line_to_box(px0, py0, px1, py1, width as f64, height as f64)
+            {
+                draw_line(
+                    &mut img,
+                    cx0.round() as i32,
+                    cy0.round() as i32,
+                    cx1.round() as i32,
+                    cy1.round() as i32,
+                    TRACK_COLOR,
+                    width as i32,
+                    height as i32,
+                );
+            }
+        }
+    }
+
+    encode_png(img)
+}
+
+/// Load every point from a single Parquet file. File-level failures (the
+/// file can't be opened or isn't valid Parquet) are propagated so the
+/// caller can report them instead of the file vanishing silently; a row
+/// whose longitude, latitude, or timestamp is missing or the wrong type is
+/// skipped and logged with the specific reason instead.
+fn load_points_from_file(path: &Path) -> Result<Vec<(DataPoint, DateTime<Utc>)>> {
+    let file = File::open(path)?;
+    let reader = SerializedFileReader::new(file)?;
+    let mut points = Vec::new();
+    for (i, record) in reader.get_row_iter(None)?.enumerate() {
+        let row = match record {
+            Ok(row) => row,
+            Err(e) => {
+                debug_log!("skipping unreadable row {} in {:?}: {}", i, path, e);
+                continue;
+            }
+        };
+        let x = match get_f64_by_name(&row, "longitude") {
+            Ok(v) => v,
+            Err(e) => {
+                debug_log!("skipping row {} in {:?}: {}", i, path, e);
+                continue;
+            }
+        };
+        let y = match get_f64_by_name(&row, "latitude") {
+            Ok(v) => v,
+            Err(e) => {
+                debug_log!("skipping row {} in {:?}: {}", i, path, e);
+                continue;
+            }
+        };
+        let mmsi = get_i64_by_name(&row, "MMSI");
+        match get_datetime_by_name(&row, "BaseDateTime") {
+            Ok(ts) => {
+                debug_log!("row x={} y={} mmsi={:?} ts={:?}", x, y, mmsi, ts);
+                points.push((DataPoint { x, y, mmsi, timestamp: ts }, ts));
+            }
+            Err(e) => {
+                debug_log!("skipping row {} in {:?}: {}", i, path, e);
+            }
+        }
+    }
+    Ok(points)
 }
 
 fn load_points_from_dir<P: AsRef<Path>>(
     dir: P,
     start: Option<DateTime<Utc>>,
     end: Option<DateTime<Utc>>,
-) -> Vec<DataPoint> {
+) -> Result<Vec<DataPoint>> {
     debug_log!("loading points from {:?} start={:?} end={:?}", dir.as_ref(), start, end);
     let mut all_points: Vec<(DataPoint, DateTime<Utc>)> = Vec::new();
     let mut max_time: Option<DateTime<Utc>> = None;
@@ -153,93 +634,112 @@ fn load_points_from_dir<P: AsRef<Path>>(
             .unwrap_or(false)
         {
             debug_log!("processing file {:?}", entry.path());
-            if let Ok(file) = File::open(entry.path()) {
-                if let Ok(reader) = SerializedFileReader::new(file) {
-                    if let Ok(iter) = reader.get_row_iter(None) {
-                        for record in iter {
-                            if let Ok(row) = record {
-                                let x = get_f64_by_name(&row, "longitude").unwrap_or(0.0);
-                                let y = get_f64_by_name(&row, "latitude").unwrap_or(0.0);
-                                if let Some(ts) = get_datetime_by_name(&row, "BaseDateTime") {
-                                    if max_time.map(|m| ts > m).unwrap_or(true) {
-                                        max_time = Some(ts);
-                                    }
-                                    debug_log!("row x={} y={} ts={:?}", x, y, ts);
-                                    all_points.push((DataPoint { x, y }, ts));
-                                }
-                            }
+            match load_points_from_file(entry.path()) {
+                Ok(points) => {
+                    for (pt, ts) in points {
+                        if max_time.map(|m| ts > m).unwrap_or(true) {
+                            max_time = Some(ts);
                         }
+                        all_points.push((pt, ts));
                     }
                 }
+                Err(e) => {
+                    eprintln!("failed to read {:?}: {}", entry.path(), e);
+                }
             }
         }
     }
-    if let Some(max) = max_time {
-        let end_time = end.unwrap_or(max);
-        let start_time = start.unwrap_or(end_time - Duration::hours(24));
-        let result: Vec<DataPoint> = all_points
-            .into_iter()
-            .filter(|(_, ts)| *ts >= start_time && *ts <= end_time)
-            .map(|(pt, _)| pt)
-            .collect();
-        debug_log!("points in range: {}", result.len());
-        result
-    } else {
-        debug_log!("no points found");
-        Vec::new()
+
+    let max = max_time.ok_or(Error::NoDataInRange)?;
+    let end_time = end.unwrap_or(max);
+    let start_time = start.unwrap_or(end_time - Duration::hours(24));
+    let result: Vec<DataPoint> = all_points
+        .into_iter()
+        .filter(|(_, ts)| *ts >= start_time && *ts <= end_time)
+        .map(|(pt, _)| pt)
+        .collect();
+    debug_log!("points in range: {}", result.len());
+    Ok(result)
+}
+
+fn get_f64_by_name(row: &parquet::record::Row, name: &str) -> Result<f64> {
+    for (n, field) in row.get_column_iter() {
+        if n == name {
+            let res = match field {
+                Field::Double(v) => Ok(*v),
+                Field::Float(v) => Ok(*v as f64),
+                Field::Int(v) => Ok(*v as f64),
+                Field::Long(v) => Ok(*v as f64),
+                Field::UInt(v) => Ok(*v as f64),
+                Field::ULong(v) => Ok(*v as f64),
+                other => Err(Error::BadColumn(format!(
+                    "column {:?} has unexpected type {:?}, expected a number",
+                    name, other
+                ))),
+            };
+            debug_log!("get_f64_by_name {} -> {:?}", name, res);
+            return res;
+        }
     }
+    Err(Error::BadColumn(format!("missing column {:?}", name)))
 }
 
-fn get_f64_by_name(row: &parquet::record::Row, name: &str) -> Option<f64> {
+fn get_i64_by_name(row: &parquet::record::Row, name: &str) -> Option<i64> {
     for (n, field) in row.get_column_iter() {
         if n == name {
             let res = match field {
-                Field::Double(v) => Some(*v),
-                Field::Float(v) => Some(*v as f64),
-                Field::Int(v) => Some(*v as f64),
-                Field::Long(v) => Some(*v as f64),
-                Field::UInt(v) => Some(*v as f64),
-                Field::ULong(v) => Some(*v as f64),
+                Field::Int(v) => Some(*v as i64),
+                Field::Long(v) => Some(*v),
+                Field::UInt(v) => Some(*v as i64),
+                Field::ULong(v) => Some(*v as i64),
                 _ => None,
             };
-            debug_log!("get_f64_by_name {} -> {:?}", name, res);
+            debug_log!("get_i64_by_name {} -> {:?}", name, res);
             return res;
         }
     }
     None
 }
 
-fn get_datetime_by_name(row: &parquet::record::Row, name: &str) -> Option<DateTime<Utc>> {
+fn get_datetime_by_name(row: &parquet::record::Row, name: &str) -> Result<DateTime<Utc>> {
+    let bad_value = |name: &str| Error::BadColumn(format!("column {:?} has an unparseable value", name));
     for (n, field) in row.get_column_iter() {
         if n == name {
             let res = match field {
-                Field::TimestampMillis(v) => DateTime::from_timestamp_millis(*v).map(|dt| dt.with_timezone(&Utc)),
-                Field::TimestampMicros(v) => DateTime::from_timestamp_micros(*v).map(|dt| dt.with_timezone(&Utc)),
-                Field::Int(v) => DateTime::from_timestamp(*v as i64, 0).map(|dt| dt.with_timezone(&Utc)),
-                Field::Long(v) => DateTime::from_timestamp(*v, 0).map(|dt| dt.with_timezone(&Utc)),
-                Field::Date(v) => {
-                    NaiveDateTime::from_timestamp_opt((*v as i64) * 86_400, 0)
-                        .map(|nd| Utc.from_utc_datetime(&nd))
+                Field::TimestampMillis(v) => {
+                    DateTime::from_timestamp_millis(*v).map(|dt| dt.with_timezone(&Utc)).ok_or_else(|| bad_value(name))
                 }
-                Field::Str(s) => {
-                    DateTime::parse_from_rfc3339(s)
-                        .map(|dt| dt.with_timezone(&Utc))
-                        .or_else(|_| {
-                            NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
-                                .map(|nd| Utc.from_utc_datetime(&nd))
-                        })
-                        .ok()
+                Field::TimestampMicros(v) => {
+                    DateTime::from_timestamp_micros(*v).map(|dt| dt.with_timezone(&Utc)).ok_or_else(|| bad_value(name))
                 }
-                _ => None,
+                Field::Int(v) => {
+                    DateTime::from_timestamp(*v as i64, 0).map(|dt| dt.with_timezone(&Utc)).ok_or_else(|| bad_value(name))
+                }
+                Field::Long(v) => {
+                    DateTime::from_timestamp(*v, 0).map(|dt| dt.with_timezone(&Utc)).ok_or_else(|| bad_value(name))
+                }
+                Field::Date(v) => DateTime::from_timestamp((*v as i64) * 86_400, 0)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .ok_or_else(|| bad_value(name)),
+                Field::Str(s) => DateTime::parse_from_rfc3339(s)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .or_else(|_| {
+                        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").map(|nd| Utc.from_utc_datetime(&nd))
+                    })
+                    .map_err(|_| bad_value(name)),
+                other => Err(Error::BadColumn(format!(
+                    "column {:?} has unexpected type {:?}, expected a timestamp",
+                    name, other
+                ))),
             };
             debug_log!("get_datetime_by_name {} -> {:?}", name, res);
             return res;
         }
     }
-    None
+    Err(Error::BadColumn(format!("missing column {:?}", name)))
 }
 
-fn parse_input_datetime(s: &str, end_of_day: bool) -> Option<DateTime<Utc>> {
+fn parse_input_datetime(s: &str, end_of_day: bool) -> Result<DateTime<Utc>> {
     DateTime::parse_from_rfc3339(s)
         .map(|dt| dt.with_timezone(&Utc))
         .or_else(|_| {
@@ -252,7 +752,7 @@ fn parse_input_datetime(s: &str, end_of_day: bool) -> Option<DateTime<Utc>> {
                 DateTime::<Utc>::from_naive_utc_and_offset(dt, Utc)
             })
         })
-        .ok()
+        .map_err(|_| Error::BadDateTime(s.to_string()))
 }
 
 #[get("/")]
@@ -261,13 +761,191 @@ async fn index() -> impl Responder {
     fs::NamedFile::open("./www/index.html")
 }
 
+#[derive(Deserialize)]
+struct TileParams {
+    /// Render a single bin by index, bypassing `t`/`window`.
+    bin: Option<usize>,
+    /// Center of the requested time window, RFC 3339.
+    t: Option<String>,
+    /// Width of the window around `t`, in hours. Defaults to the bin width.
+    window: Option<f64>,
+    /// Color ramp for density. Defaults to the original red/blue look.
+    cmap: Option<ColorMap>,
+    /// Density normalization. Defaults to the original logarithmic scale.
+    norm: Option<Normalization>,
+    /// Fixed denominator used when `norm=fixed`, so tiles at different
+    /// zoom levels stay comparable instead of each being scaled to its own max.
+    max: Option<f32>,
+}
+
 #[get("/tiles/{zoom}/{x}/{y}.png")]
-async fn tile(path: web::Path<(u32, u32, u32)>, data: web::Data<AppState>) -> HttpResponse {
+async fn tile(path: web::Path<(u32, u32, u32)>, query: web::Query<TileParams>, data: web::Data<AppState>) -> HttpResponse {
     let (z, x, y) = path.into_inner();
     debug_log!("tile request z={} x={} y={}", z, x, y);
-    let tree = data.tree.lock().unwrap();
-    let img = generate_tile(z, x, y, &tree);
-    HttpResponse::Ok().content_type("image/png").body(img)
+    let bins = match data.bins.read() {
+        Ok(bins) => bins,
+        Err(_) => {
+            eprintln!("{}", Error::LockPoisoned);
+            return HttpResponse::InternalServerError().body("bins lock poisoned");
+        }
+    };
+
+    let indices = if let Some(bin) = query.bin {
+        if bin >= bins.trees.len() {
+            return HttpResponse::BadRequest().body("bin index out of range");
+        }
+        vec![bin]
+    } else if let Some(t) = &query.t {
+        let center = match parse_input_datetime(t, false) {
+            Ok(dt) => dt,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        };
+        let window_hours = query
+            .window
+            .unwrap_or_else(|| bins.bin_width.num_milliseconds() as f64 / 3_600_000.0);
+        let half = Duration::milliseconds((window_hours * 3_600_000.0 / 2.0) as i64);
+        bins.intersecting(center - half, center + half)
+    } else {
+        bins.all_indices()
+    };
+
+    let cmap = query.cmap.unwrap_or_default();
+    let norm = query.norm.unwrap_or_default();
+    let fixed_max = query.max.unwrap_or(DEFAULT_FIXED_MAX);
+
+    let cache_key = TileCacheKey {
+        zoom: z,
+        x,
+        y,
+        dataset_version: data.dataset_version.load(Ordering::Acquire),
+        indices: indices.clone(),
+        cmap,
+        norm,
+        fixed_max_bits: fixed_max.to_bits(),
+    };
+    if let Ok(mut cache) = data.tile_cache.lock() {
+        if let Some(cached) = cache.get(&cache_key) {
+            debug_log!("tile cache hit z={} x={} y={}", z, x, y);
+            return HttpResponse::Ok().content_type("image/png").body(cached.clone());
+        }
+    }
+
+    match generate_tile(z, x, y, &bins, &indices, cmap, norm, fixed_max) {
+        Ok(img) => {
+            if let Ok(mut cache) = data.tile_cache.lock() {
+                cache.put(cache_key, img.clone());
+            }
+            HttpResponse::Ok().content_type("image/png").body(img)
+        }
+        Err(e) => {
+            eprintln!("failed to generate tile z={} x={} y={}: {}", z, x, y, e);
+            HttpResponse::InternalServerError().body("failed to generate tile")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NearestParams {
+    lng: f64,
+    lat: f64,
+    k: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct NearestFeature {
+    lng: f64,
+    lat: f64,
+    distance_m: f64,
+}
+
+/// Click-to-identify support: find the `k` points closest to a lng/lat,
+/// reported with their true great-circle distance. `DataPoint` stores
+/// WGS84 degrees (the same space `build_tracks`'s haversine math uses), so
+/// the query point is searched against the trees directly with no
+/// projection; `nearest_neighbor_iter` is just used to narrow down
+/// candidates per bin before the exact haversine distance is computed.
+///
+/// A degree isn't a constant distance -- a degree of longitude shrinks by
+/// `cos(lat)` away from the equator -- so `nearest_neighbor_iter`'s
+/// degree-space order is not the same as true great-circle order. Each bin
+/// hands the haversine re-sort its `max(k, NEAREST_OVERSAMPLE_MIN)`
+/// degree-nearest points rather than just `k`. A point ranked past that
+/// count in its bin's degree order is never considered, so the result is not
+/// guaranteed to be the true great-circle nearest; once `k` reaches
+/// `NEAREST_OVERSAMPLE_MIN` there is no margin past `k` at all.
+const NEAREST_OVERSAMPLE_MIN: usize = 20;
+
+/// Find up to `k` points near `(lng, lat)`, ordered by great-circle distance,
+/// taken from each bin's degree-space candidates as described above.
+fn k_nearest(bins: &Bins, lng: f64, lat: f64, k: usize) -> Vec<DataPoint> {
+    let per_bin = k.max(NEAREST_OVERSAMPLE_MIN);
+    let mut candidates: Vec<DataPoint> = bins
+        .trees
+        .iter()
+        .flat_map(|tree| tree.nearest_neighbor_iter(&[lng, lat]).take(per_bin).copied())
+        .collect();
+    candidates.sort_by(|a, b| {
+        let da = haversine_distance_m(lng, lat, a.x, a.y);
+        let db = haversine_distance_m(lng, lat, b.x, b.y);
+        da.partial_cmp(&db).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    candidates.truncate(k);
+    candidates
+}
+
+#[get("/query")]
+async fn query_nearest(query: web::Query<NearestParams>, data: web::Data<AppState>) -> HttpResponse {
+    let k = query.k.unwrap_or(1).max(1);
+    debug_log!("nearest query lng={} lat={} k={}", query.lng, query.lat, k);
+    let bins = match data.bins.read() {
+        Ok(bins) => bins,
+        Err(_) => {
+            eprintln!("{}", Error::LockPoisoned);
+            return HttpResponse::InternalServerError().body("bins lock poisoned");
+        }
+    };
+
+    let results: Vec<NearestFeature> = k_nearest(&bins, query.lng, query.lat, k)
+        .into_iter()
+        .map(|p| NearestFeature {
+            lng: p.x,
+            lat: p.y,
+            distance_m: haversine_distance_m(query.lng, query.lat, p.x, p.y),
+        })
+        .collect();
+    HttpResponse::Ok().json(results)
+}
+
+#[get("/bins")]
+async fn bins_endpoint(data: web::Data<AppState>) -> HttpResponse {
+    let bins = match data.bins.read() {
+        Ok(bins) => bins,
+        Err(_) => {
+            eprintln!("{}", Error::LockPoisoned);
+            return HttpResponse::InternalServerError().body("bins lock poisoned");
+        }
+    };
+    HttpResponse::Ok().json(&bins.bins)
+}
+
+#[get("/tracks/{zoom}/{x}/{y}.png")]
+async fn tracks_tile(path: web::Path<(u32, u32, u32)>, data: web::Data<AppState>) -> HttpResponse {
+    let (z, x, y) = path.into_inner();
+    debug_log!("tracks tile request z={} x={} y={}", z, x, y);
+    let points = match data.points.read() {
+        Ok(points) => points,
+        Err(_) => {
+            eprintln!("{}", Error::LockPoisoned);
+            return HttpResponse::InternalServerError().body("points lock poisoned");
+        }
+    };
+    match generate_track_tile(z, x, y, &points) {
+        Ok(img) => HttpResponse::Ok().content_type("image/png").body(img),
+        Err(e) => {
+            eprintln!("failed to generate track tile z={} x={} y={}: {}", z, x, y, e);
+            HttpResponse::InternalServerError().body("failed to generate track tile")
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -278,20 +956,44 @@ struct RangeParams {
 
 #[get("/range")]
 async fn range(query: web::Query<RangeParams>, data: web::Data<AppState>) -> HttpResponse {
-    let start = query
-        .start
-        .as_deref()
-        .and_then(|s| parse_input_datetime(s, false));
-    let end = query
-        .end
-        .as_deref()
-        .and_then(|s| parse_input_datetime(s, true));
-    let points = load_points_from_dir("partition", start, end);
-    let tree = RTree::bulk_load(points);
-    {
-        let mut t = data.tree.lock().unwrap();
-        *t = tree;
+    let start = match query.start.as_deref().map(|s| parse_input_datetime(s, false)) {
+        Some(Ok(dt)) => Some(dt),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+        None => None,
+    };
+    let end = match query.end.as_deref().map(|s| parse_input_datetime(s, true)) {
+        Some(Ok(dt)) => Some(dt),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+        None => None,
+    };
+
+    let points = match load_points_from_dir("partition", start, end) {
+        Ok(points) => points,
+        Err(Error::NoDataInRange) => return HttpResponse::BadRequest().body("no data available for the requested range"),
+        Err(e) => {
+            eprintln!("failed to reload points: {}", e);
+            return HttpResponse::InternalServerError().body("failed to reload points");
+        }
+    };
+    let bin_width = Duration::milliseconds((bin_width_hours() * 3_600_000.0) as i64);
+    let bucketed = bucket_points(points.clone(), bin_width);
+    match data.bins.write() {
+        Ok(mut b) => *b = bucketed,
+        Err(_) => {
+            eprintln!("{}", Error::LockPoisoned);
+            return HttpResponse::InternalServerError().body("bins lock poisoned");
+        }
     }
+    match data.points.write() {
+        Ok(mut p) => *p = points,
+        Err(_) => {
+            eprintln!("{}", Error::LockPoisoned);
+            return HttpResponse::InternalServerError().body("points lock poisoned");
+        }
+    }
+    // Bump the dataset version so cached tiles keyed against the old data
+    // simply stop being matched, instead of needing to be scanned and evicted.
+    data.dataset_version.fetch_add(1, Ordering::AcqRel);
     HttpResponse::Ok().body("ok")
 }
 
@@ -299,16 +1001,29 @@ async fn range(query: web::Query<RangeParams>, data: web::Data<AppState>) -> Htt
 async fn main() -> std::io::Result<()> {
     debug_log!("starting server");
     let base_path = "partition";
-    let points = load_points_from_dir(base_path, None, None);
+    let points = match load_points_from_dir(base_path, None, None) {
+        Ok(points) => points,
+        Err(e) => {
+            eprintln!("failed to load initial points: {}", e);
+            Vec::new()
+        }
+    };
     debug_log!("loaded {} points", points.len());
-    let tree = Arc::new(Mutex::new(RTree::bulk_load(points)));
-    let data = web::Data::new(AppState { tree });
+    let bin_width = Duration::milliseconds((bin_width_hours() * 3_600_000.0) as i64);
+    let bins = Arc::new(RwLock::new(bucket_points(points.clone(), bin_width)));
+    let points = Arc::new(RwLock::new(points));
+    let tile_cache = Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(TILE_CACHE_CAPACITY).unwrap())));
+    let dataset_version = Arc::new(AtomicU64::new(0));
+    let data = web::Data::new(AppState { bins, points, tile_cache, dataset_version });
 
     HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
             .service(index)
             .service(tile)
+            .service(bins_endpoint)
+            .service(query_nearest)
+            .service(tracks_tile)
             .service(range)
             .service(fs::Files::new("/lib", "./www/lib"))
     })
@@ -316,3 +1031,114 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn pt(x: f64, y: f64, mmsi: i64, secs: i64) -> DataPoint {
+        DataPoint { x, y, mmsi: Some(mmsi), timestamp: dt(secs) }
+    }
+
+    #[test]
+    fn haversine_distance_matches_known_reference() {
+        // New York (40.7128 N, 74.0060 W) to London (51.5074 N, 0.1278 W):
+        // commonly cited great-circle distance is ~5,570 km.
+        let d = haversine_distance_m(-74.0060, 40.7128, -0.1278, 51.5074);
+        assert!((d - 5_570_000.0).abs() < 20_000.0, "unexpected distance: {}", d);
+    }
+
+    #[test]
+    fn haversine_distance_zero_for_identical_points() {
+        assert_eq!(haversine_distance_m(-74.0, 40.7, -74.0, 40.7), 0.0);
+    }
+
+    #[test]
+    fn build_tracks_splits_on_time_gap() {
+        let points = vec![
+            pt(0.0, 0.0, 1, 0),
+            pt(0.001, 0.001, 1, 60),
+            pt(0.002, 0.002, 1, 60 + (TRACK_GAP_MINUTES + 1) * 60),
+        ];
+        let segments = build_tracks(&points);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].len(), 2);
+    }
+
+    #[test]
+    fn build_tracks_splits_on_implausible_speed() {
+        // Same one-second gap as a normal fix, but 10 degrees of longitude
+        // apart -- no real vessel can cover that distance in 1 second.
+        let points = vec![pt(0.0, 0.0, 1, 0), pt(10.0, 0.0, 1, 1)];
+        let segments = build_tracks(&points);
+        assert!(segments.is_empty());
+    }
+
+    #[test]
+    fn build_tracks_drops_points_without_mmsi() {
+        let points = vec![DataPoint { x: 0.0, y: 0.0, mmsi: None, timestamp: dt(0) }];
+        assert!(build_tracks(&points).is_empty());
+    }
+
+    #[test]
+    fn clip_line_to_box_clips_to_visible_portion() {
+        let clipped = clip_line_to_box(-10.0, 5.0, 10.0, 5.0, 8.0, 8.0);
+        assert_eq!(clipped, Some((0.0, 5.0, 8.0, 5.0)));
+    }
+
+    #[test]
+    fn clip_line_to_box_rejects_line_entirely_outside() {
+        assert_eq!(clip_line_to_box(-10.0, -10.0, -5.0, -5.0, 8.0, 8.0), None);
+    }
+
+    #[test]
+    fn viridis_lut_endpoints_match_stops() {
+        let lut = viridis_lut();
+        assert_eq!(lut[0], Rgba([68, 1, 84, 255]));
+        assert_eq!(lut[255], Rgba([253, 231, 37, 255]));
+    }
+
+    #[test]
+    fn inferno_lut_endpoints_match_stops() {
+        let lut = inferno_lut();
+        assert_eq!(lut[0], Rgba([0, 0, 4, 255]));
+        assert_eq!(lut[255], Rgba([252, 255, 164, 255]));
+    }
+
+    #[test]
+    fn build_lut_interpolates_between_stops() {
+        let stops = [(0.0, [0, 0, 0]), (1.0, [255, 255, 255])];
+        let lut = build_lut(&stops);
+        assert_eq!(lut[0], Rgba([0, 0, 0, 255]));
+        assert_eq!(lut[255], Rgba([255, 255, 255, 255]));
+        // Midpoint should be roughly half-gray.
+        let mid = lut[128];
+        assert!(mid.0[0] > 120 && mid.0[0] < 135, "unexpected midpoint: {:?}", mid);
+    }
+
+    #[test]
+    fn k_nearest_prefers_true_distance_over_degree_distance() {
+        // At lat=60, a degree of longitude is worth cos(60) = 0.5 of a
+        // degree of latitude in true distance. `a` is farther in raw
+        // degree-space than `b` (0.10 vs 0.06) but closer in true
+        // great-circle distance -- nearest_neighbor_iter's degree-space
+        // order would rank `b` first, so without oversampling past k=1,
+        // `a` would never reach the haversine re-sort.
+        let (lng, lat) = (0.0, 60.0);
+        let a = pt(lng + 0.10, lat, 1, 0);
+        let b = pt(lng, lat + 0.06, 2, 0);
+        let bins = Bins {
+            bin_width: Duration::hours(1),
+            bins: vec![TimeBin { start: dt(0), end: dt(3_600) }],
+            trees: vec![RTree::bulk_load(vec![a, b])],
+        };
+
+        let nearest = k_nearest(&bins, lng, lat, 1);
+        assert_eq!(nearest.len(), 1);
+        assert_eq!(nearest[0].mmsi, Some(1), "expected the true-nearest point (a), not the degree-nearest one (b)");
+    }
+}